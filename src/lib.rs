@@ -27,8 +27,8 @@
 //!
 //! ```text
 //! 8.39ms           top_level
-//!  8.35ms    4      middle
-//!    2.13ms    2      leaf
+//!  8.35ms    4      (min 1.06ms, max 3.12ms, avg 2.09ms) middle
+//!    2.13ms    2      (min 1.06ms, max 1.06ms, avg 1.06ms) leaf
 //! ```
 
 use std::{
@@ -43,7 +43,7 @@ use tracing::{
     debug,
     field::{Field, Visit},
     span::Attributes,
-    Event, Id, Subscriber,
+    Event, Id, Level, Metadata, Subscriber,
 };
 use tracing_subscriber::{
     fmt::MakeWriter,
@@ -103,19 +103,70 @@ macro_rules! select {
 }
 
 pub fn span_tree() -> SpanTree {
-    SpanTree { aggregate: false, writer: io::stderr }
+    SpanTree {
+        aggregate: false,
+        json: false,
+        dot: false,
+        self_time: false,
+        directives: Vec::new(),
+        writer: io::stderr,
+    }
 }
 
 pub fn span_tree_with<W: for<'a> MakeWriter<'a>>(writer: W) -> SpanTree<W> {
-    SpanTree { aggregate: false, writer }
+    SpanTree {
+        aggregate: false,
+        json: false,
+        dot: false,
+        self_time: false,
+        directives: Vec::new(),
+        writer,
+    }
 }
 
 #[derive(Default)]
 pub struct SpanTree<W = fn() -> Stderr> {
     aggregate: bool,
+    json: bool,
+    dot: bool,
+    self_time: bool,
+    directives: Vec<Directive>,
     writer: W,
 }
 
+/// A single `target=level` (or bare `target`) directive, following
+/// `tracing_subscriber::EnvFilter` syntax.
+struct Directive {
+    target: String,
+    level: Option<Level>,
+}
+
+impl Directive {
+    fn parse(s: &str) -> Self {
+        let s = s.trim();
+        match s.split_once('=') {
+            Some((target, level)) => {
+                let level = level.trim();
+                let level = level
+                    .parse()
+                    .unwrap_or_else(|_| panic!("invalid level `{level}` in filter directive `{s}`"));
+                Directive { target: target.trim().to_string(), level: Some(level) }
+            }
+            None => Directive { target: s.to_string(), level: None },
+        }
+    }
+
+    fn matches(&self, metadata: &Metadata<'_>) -> bool {
+        if !metadata.target().starts_with(self.target.as_str()) {
+            return false;
+        }
+        match self.level {
+            Some(level) => *metadata.level() <= level,
+            None => true,
+        }
+    }
+}
+
 impl<W> SpanTree<W>
 where
     W: for<'a> MakeWriter<'a> + Send + Sync + 'static,
@@ -124,6 +175,29 @@ where
     pub fn aggregate(self, yes: bool) -> Self {
         Self { aggregate: yes, ..self }
     }
+    /// Emit each root span tree as a single JSON object instead of an indented
+    /// ASCII tree.
+    pub fn json(self, yes: bool) -> Self {
+        Self { json: yes, ..self }
+    }
+    /// Emit each root span tree as a Graphviz `digraph`, suitable for piping
+    /// into `dot -Tsvg`.
+    pub fn dot(self, yes: bool) -> Self {
+        Self { dot: yes, ..self }
+    }
+    /// Print each span's exclusive (self) time -- its duration minus the
+    /// summed duration of its direct children -- as an extra column.
+    pub fn self_time(self, yes: bool) -> Self {
+        Self { self_time: yes, ..self }
+    }
+    /// Only instrument spans matching a comma-separated list of `target=level`
+    /// (or bare `target`) directives, `tracing_subscriber::EnvFilter`-style.
+    /// Spans that don't match are skipped; their children attach to the
+    /// nearest matching ancestor.
+    pub fn filter(self, directives: &str) -> Self {
+        let directives = directives.split(',').map(Directive::parse).collect();
+        Self { directives, ..self }
+    }
     /// Set as a global subscriber
     pub fn enable(self) {
         let subscriber = Registry::default().with(self);
@@ -134,22 +208,53 @@ where
 
 struct Data {
     start: Instant,
-    children: Vec<Node>,
+    fields: Vec<(&'static str, String)>,
+    children: Vec<Child>,
 }
 
 impl Data {
     fn new(attrs: &Attributes<'_>) -> Self {
-        let mut span = Self { start: Instant::now(), children: Vec::new() };
+        let mut span = Self { start: Instant::now(), fields: Vec::new(), children: Vec::new() };
         attrs.record(&mut span);
         span
     }
     fn into_node(self, name: &'static str) -> Node {
-        Node { name, count: 1, duration: self.start.elapsed(), children: self.children }
+        let duration = self.start.elapsed();
+        Node {
+            name,
+            count: 1,
+            duration,
+            min: duration,
+            max: duration,
+            self_duration: Duration::default(),
+            fields: self.fields,
+            children: self.children,
+        }
     }
 }
 
 impl Visit for Data {
-    fn record_debug(&mut self, _field: &Field, _value: &dyn fmt::Debug) {}
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.fields.push((field.name(), format!("{value:?}")));
+    }
+}
+
+/// An event recorded with `tracing::info!`-style macros, captured the same
+/// way as span fields.
+#[derive(Default)]
+struct EventVisitor {
+    message: String,
+    fields: Vec<(&'static str, String)>,
+}
+
+impl Visit for EventVisitor {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        if field.name() == "message" {
+            self.message = format!("{value:?}");
+        } else {
+            self.fields.push((field.name(), format!("{value:?}")));
+        }
+    }
 }
 
 impl<S, W> Layer<S> for SpanTree<W>
@@ -157,6 +262,10 @@ where
     W: for<'a> MakeWriter<'a> + 'static,
     S: Subscriber + for<'span> LookupSpan<'span>,
 {
+    fn enabled(&self, metadata: &Metadata<'_>, _ctx: Context<'_, S>) -> bool {
+        self.directives.is_empty() || self.directives.iter().any(|d| d.matches(metadata))
+    }
+
     fn on_new_span(&self, attrs: &Attributes, id: &Id, ctx: Context<S>) {
         let span = ctx.span(id).unwrap();
 
@@ -164,7 +273,26 @@ where
         span.extensions_mut().insert(data);
     }
 
-    fn on_event(&self, _event: &Event<'_>, _ctx: Context<S>) {}
+    fn on_event(&self, event: &Event<'_>, ctx: Context<S>) {
+        let span = match ctx.event_span(event) {
+            Some(span) => span,
+            None => return,
+        };
+        let mut extensions = span.extensions_mut();
+        let data = match extensions.get_mut::<Data>() {
+            Some(data) => data,
+            None => return,
+        };
+
+        let mut visitor = EventVisitor::default();
+        event.record(&mut visitor);
+        let at = data.start.elapsed();
+        data.children.push(Child::Event {
+            message: visitor.message,
+            fields: visitor.fields,
+            at,
+        });
+    }
 
     fn on_close(&self, id: Id, ctx: Context<S>) {
         let span = ctx.span(&id).unwrap();
@@ -173,9 +301,14 @@ where
 
         match span.parent() {
             Some(parent_span) => {
-                parent_span.extensions_mut().get_mut::<Data>().unwrap().children.push(node);
+                parent_span
+                    .extensions_mut()
+                    .get_mut::<Data>()
+                    .unwrap()
+                    .children
+                    .push(Child::Span(node));
             }
-            None => node.print(self.aggregate, &self.writer),
+            None => node.print(self.aggregate, self.json, self.dot, self.self_time, &self.writer),
         }
     }
 }
@@ -185,7 +318,64 @@ struct Node {
     name: &'static str,
     count: u32,
     duration: Duration,
-    children: Vec<Node>,
+    min: Duration,
+    max: Duration,
+    self_duration: Duration,
+    fields: Vec<(&'static str, String)>,
+    children: Vec<Child>,
+}
+
+enum Child {
+    Span(Node),
+    Event { message: String, fields: Vec<(&'static str, String)>, at: Duration },
+}
+
+fn format_fields(fields: &[(&'static str, String)]) -> String {
+    if fields.is_empty() {
+        return String::new();
+    }
+    let mut buf = String::from("{");
+    for (i, (name, value)) in fields.iter().enumerate() {
+        if i > 0 {
+            buf.push_str(", ");
+        }
+        buf.push_str(name);
+        buf.push('=');
+        buf.push_str(value);
+    }
+    buf.push('}');
+    buf
+}
+
+fn write_json_string(buf: &mut String, s: &str) {
+    buf.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => buf.push_str("\\\""),
+            '\\' => buf.push_str("\\\\"),
+            '\n' => buf.push_str("\\n"),
+            '\r' => buf.push_str("\\r"),
+            '\t' => buf.push_str("\\t"),
+            c if (c as u32) < 0x20 => buf.push_str(&format!("\\u{:04x}", c as u32)),
+            c => buf.push(c),
+        }
+    }
+    buf.push('"');
+}
+
+/// Escapes a string for embedding in a Graphviz DOT label (a quoted string
+/// literal), as opposed to `write_json_string`, which escapes for JSON.
+fn escape_dot_label(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for c in s.chars() {
+        match c {
+            '"' => out.push_str("\\\""),
+            '\\' => out.push_str("\\\\"),
+            '\n' => out.push_str("\\n"),
+            c => out.push(c),
+        }
+    }
+    out
 }
 
 fn should_style<'a, W>() -> bool
@@ -203,60 +393,241 @@ where
 }
 
 impl Node {
-    fn print<W: for<'a> MakeWriter<'a> + 'static>(&mut self, agg: bool, writer: &W) {
+    fn print<W: for<'a> MakeWriter<'a> + 'static>(
+        &mut self,
+        agg: bool,
+        json: bool,
+        dot: bool,
+        self_time: bool,
+        writer: &W,
+    ) {
         if agg {
             self.aggregate()
         }
-        self.go(0, writer)
+        if self_time {
+            self.compute_self_time()
+        }
+        if dot {
+            self.go_dot(self_time, writer)
+        } else if json {
+            self.go_json(self_time, writer)
+        } else {
+            self.go(0, self_time, writer)
+        }
     }
-    fn go<W: for<'a> MakeWriter<'a> + 'static>(&self, level: usize, writer: &W) {
+    fn go<W: for<'a> MakeWriter<'a> + 'static>(&self, level: usize, self_time: bool, writer: &W) {
         let width = level * 2;
 
-        let Self { name, count, duration, .. } = self;
+        let Self { name, count, duration, min, max, self_duration, fields, .. } = self;
+        let name = format!("{name}{}", format_fields(fields));
+        let is_aggregated = *count > 1;
+        let avg = if is_aggregated { *duration / *count } else { Duration::default() };
+        // built as a String (rather than a width spec applied to format_args! output, which
+        // std::fmt::Arguments silently ignores) so the column is actually padded
+        let duration = format!("{:<9}", format!(" {duration:3.2?} "));
+        let self_duration = if self_time {
+            format!("{:<9}", format!(" {self_duration:3.2?} "))
+        } else {
+            String::new()
+        };
 
         // avoid intermediate allocations
         let_workaround! {
             let name = style!(W, BOLD, name);
-            let count = select!(*count > 1, format_args!(" {count:<6} "), format_args!(" "));
-            let duration = format_args!(" {duration:3.2?} ");
+            let count = select!(is_aggregated, format_args!(" {count:<6} "), format_args!(" "));
+            let stats = select!(
+                is_aggregated,
+                format_args!("(min {min:3.2?}, max {max:3.2?}, avg {avg:3.2?}) "),
+                format_args!("")
+            );
 
             let _ = writeln!(
                 writer.make_writer(),
-                "{s:width$}{duration:<9}{count}{name}",
+                "{s:width$}{self_duration}{duration}{count}{stats}{name}",
                 s = "",
             );
         }
 
         for child in &self.children {
-            child.go(level + 1, writer)
+            match child {
+                Child::Span(node) => node.go(level + 1, self_time, writer),
+                Child::Event { message, fields, .. } => {
+                    let width = (level + 1) * 2;
+                    let line = format!("{message}{}", format_fields(fields));
+                    let _ = writeln!(writer.make_writer(), "{s:width$}> {line}", s = "");
+                }
+            }
         }
         if level == 0 {
             let _ = writeln!(writer.make_writer());
         }
     }
 
+    fn compute_self_time(&mut self) {
+        let children_duration: Duration = self
+            .children
+            .iter()
+            .map(|child| match child {
+                Child::Span(node) => node.duration,
+                Child::Event { .. } => Duration::default(),
+            })
+            .sum();
+        self.self_duration = self.duration.saturating_sub(children_duration);
+
+        for child in &mut self.children {
+            if let Child::Span(node) = child {
+                node.compute_self_time();
+            }
+        }
+    }
+
+    fn go_json<W: for<'a> MakeWriter<'a> + 'static>(&self, self_time: bool, writer: &W) {
+        let mut buf = String::new();
+        self.write_json(&mut buf, self_time);
+        let _ = writeln!(writer.make_writer(), "{buf}");
+    }
+
+    fn write_json(&self, buf: &mut String, self_time: bool) {
+        buf.push_str("{\"name\":");
+        write_json_string(buf, self.name);
+        buf.push_str(",\"duration_ns\":");
+        buf.push_str(&self.duration.as_nanos().to_string());
+        if self_time {
+            buf.push_str(",\"self_duration_ns\":");
+            buf.push_str(&self.self_duration.as_nanos().to_string());
+        }
+        buf.push_str(",\"count\":");
+        buf.push_str(&self.count.to_string());
+        if self.count > 1 {
+            buf.push_str(",\"min_ns\":");
+            buf.push_str(&self.min.as_nanos().to_string());
+            buf.push_str(",\"max_ns\":");
+            buf.push_str(&self.max.as_nanos().to_string());
+            buf.push_str(",\"avg_ns\":");
+            buf.push_str(&(self.duration / self.count).as_nanos().to_string());
+        }
+        buf.push_str(",\"fields\":{");
+        for (i, (name, value)) in self.fields.iter().enumerate() {
+            if i > 0 {
+                buf.push(',');
+            }
+            write_json_string(buf, name);
+            buf.push(':');
+            write_json_string(buf, value);
+        }
+        buf.push_str("},\"events\":[");
+        let mut first = true;
+        for child in &self.children {
+            if let Child::Event { message, fields, at } = child {
+                if !first {
+                    buf.push(',');
+                }
+                first = false;
+                buf.push_str("{\"message\":");
+                write_json_string(buf, message);
+                buf.push_str(",\"at_ns\":");
+                buf.push_str(&at.as_nanos().to_string());
+                buf.push_str(",\"fields\":{");
+                for (i, (name, value)) in fields.iter().enumerate() {
+                    if i > 0 {
+                        buf.push(',');
+                    }
+                    write_json_string(buf, name);
+                    buf.push(':');
+                    write_json_string(buf, value);
+                }
+                buf.push_str("}}");
+            }
+        }
+        buf.push_str("],\"children\":[");
+        let mut first = true;
+        for child in &self.children {
+            if let Child::Span(node) = child {
+                if !first {
+                    buf.push(',');
+                }
+                first = false;
+                node.write_json(buf, self_time);
+            }
+        }
+        buf.push_str("]}");
+    }
+
+    fn go_dot<W: for<'a> MakeWriter<'a> + 'static>(&self, self_time: bool, writer: &W) {
+        let mut buf = String::from("digraph {\n");
+        let mut next_id = 0u64;
+        self.write_dot(&mut buf, &mut next_id, self_time);
+        buf.push_str("}\n");
+        let _ = writeln!(writer.make_writer(), "{buf}");
+    }
+
+    fn write_dot(&self, buf: &mut String, next_id: &mut u64, self_time: bool) -> u64 {
+        let id = *next_id;
+        *next_id += 1;
+
+        let header = escape_dot_label(&format!("{}{}", self.name, format_fields(&self.fields)));
+        let mut label = format!("{header}\\n{:3.2?}", self.duration);
+        if self_time {
+            label.push_str(&format!("\\nself {:3.2?}", self.self_duration));
+        }
+        if self.count > 1 {
+            let avg = self.duration / self.count;
+            label.push_str(&format!(
+                "\\n{} (min {:3.2?}, max {:3.2?}, avg {avg:3.2?})",
+                self.count, self.min, self.max
+            ));
+        }
+        buf.push_str(&format!("  n{id} [label=\"{label}\"];\n"));
+
+        for child in &self.children {
+            if let Child::Span(node) = child {
+                let child_id = node.write_dot(buf, next_id, self_time);
+                buf.push_str(&format!("  n{id} -> n{child_id};\n"));
+            }
+        }
+        id
+    }
+
     fn aggregate(&mut self) {
         if self.children.is_empty() {
             return;
         }
 
-        self.children.sort_by_key(|it| it.name);
-        let mut idx = 0;
-        for i in 1..self.children.len() {
-            if self.children[idx].name == self.children[i].name {
-                let child = mem::take(&mut self.children[i]);
-                self.children[idx].duration += child.duration;
-                self.children[idx].count += child.count;
-                self.children[idx].children.extend(child.children);
-            } else {
-                idx += 1;
-                assert!(idx <= i);
-                self.children.swap(idx, i);
+        // Merge each span into the slot of its first occurrence, leaving events (and the
+        // relative order of everything else) untouched, so timestamp order is preserved.
+        let mut merged: Vec<Child> = Vec::new();
+        for child in mem::take(&mut self.children) {
+            let node = match child {
+                Child::Event { .. } => {
+                    merged.push(child);
+                    continue;
+                }
+                Child::Span(node) => node,
+            };
+            let existing = merged.iter_mut().find_map(|child| match child {
+                Child::Span(existing) if existing.name == node.name && existing.fields == node.fields => {
+                    Some(existing)
+                }
+                _ => None,
+            });
+            match existing {
+                Some(existing) => {
+                    existing.duration += node.duration;
+                    existing.count += node.count;
+                    existing.min = existing.min.min(node.min);
+                    existing.max = existing.max.max(node.max);
+                    existing.children.extend(node.children);
+                }
+                None => merged.push(Child::Span(node)),
             }
         }
-        self.children.truncate(idx + 1);
-        for child in &mut self.children {
-            child.aggregate()
+
+        for child in &mut merged {
+            if let Child::Span(node) = child {
+                node.aggregate()
+            }
         }
+
+        self.children = merged;
     }
 }
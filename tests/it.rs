@@ -1,4 +1,48 @@
-use std::{thread, time::Duration};
+use std::{
+    io,
+    sync::{Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use tracing_subscriber::{fmt::MakeWriter, prelude::*, registry::Registry};
+
+/// An in-memory writer so tests can assert on exactly what a given
+/// `SpanTree` mode printed, instead of sharing the (one-shot) global
+/// subscriber with every other test in the binary.
+#[derive(Clone, Default)]
+struct Buf(Arc<Mutex<Vec<u8>>>);
+
+impl Buf {
+    fn contents(&self) -> String {
+        String::from_utf8(self.0.lock().unwrap().clone()).unwrap()
+    }
+}
+
+impl io::Write for Buf {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.0.lock().unwrap().extend_from_slice(buf);
+        Ok(buf.len())
+    }
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl<'a> MakeWriter<'a> for Buf {
+    type Writer = Buf;
+    fn make_writer(&'a self) -> Buf {
+        self.clone()
+    }
+}
+
+fn run_with<L>(layer: L, f: impl FnOnce())
+where
+    L: tracing_subscriber::Layer<Registry> + Send + Sync + 'static,
+{
+    let subscriber = Registry::default().with(layer);
+    tracing::subscriber::with_default(subscriber, f);
+}
 
 #[test]
 fn not_aggregated() {
@@ -12,6 +56,111 @@ fn aggregated() {
     top_level()
 }
 
+#[test]
+fn json() {
+    let buf = Buf::default();
+    run_with(tracing_span_tree::span_tree_with(buf.clone()).json(true), top_level);
+
+    let output = buf.contents();
+    assert!(output.trim_start().starts_with('{'), "not a JSON object: {output}");
+    assert!(output.contains("\"name\":\"top_level\""));
+    assert!(output.contains("\"duration_ns\":"));
+    assert!(output.contains("\"children\":["));
+}
+
+#[test]
+fn dot() {
+    let buf = Buf::default();
+    run_with(tracing_span_tree::span_tree_with(buf.clone()).dot(true), top_level);
+
+    let output = buf.contents();
+    assert!(output.trim_start().starts_with("digraph {"), "not a digraph: {output}");
+    assert!(output.contains("label=\"top_level\\n"));
+    assert!(output.contains(" -> n"));
+}
+
+#[test]
+fn self_time() {
+    let plain = Buf::default();
+    run_with(tracing_span_tree::span_tree_with(plain.clone()), top_level);
+
+    let with_self_time = Buf::default();
+    run_with(tracing_span_tree::span_tree_with(with_self_time.clone()).self_time(true), top_level);
+
+    let plain_line = plain.contents().lines().next().unwrap().to_string();
+    let self_time_line = with_self_time.contents().lines().next().unwrap().to_string();
+    assert!(
+        self_time_line.len() > plain_line.len(),
+        "expected an extra self-time column: {plain_line:?} vs {self_time_line:?}"
+    );
+}
+
+#[test]
+fn filtered() {
+    let buf = Buf::default();
+    run_with(tracing_span_tree::span_tree_with(buf.clone()).filter("it=info"), || {
+        let _root = tracing::info_span!("root").entered();
+        let _hidden = tracing::debug_span!("hidden").entered();
+        let _visible = tracing::info_span!("visible_child").entered();
+    });
+
+    let output = buf.contents();
+    assert!(output.contains("root"));
+    assert!(output.contains("visible_child"));
+    assert!(!output.contains("hidden"));
+}
+
+#[test]
+fn aggregated_merges_identical_siblings() {
+    let buf = Buf::default();
+    run_with(tracing_span_tree::span_tree_with(buf.clone()).aggregate(true), identical_siblings);
+
+    let output = buf.contents();
+    let same_lines: Vec<&str> = output.lines().filter(|line| line.contains("same")).collect();
+    assert_eq!(same_lines.len(), 1, "expected siblings merged into one line: {output}");
+    assert!(same_lines[0].contains(" 3 "), "expected merged count 3: {same_lines:?}");
+    assert!(same_lines[0].contains("(min "));
+    assert!(same_lines[0].contains("max "));
+    assert!(same_lines[0].contains("avg "));
+
+    let json_buf = Buf::default();
+    run_with(
+        tracing_span_tree::span_tree_with(json_buf.clone()).aggregate(true).json(true),
+        identical_siblings,
+    );
+    let json = json_buf.contents();
+    assert_eq!(json.matches("\"name\":\"same\"").count(), 1, "siblings not merged: {json}");
+    // "same" is a leaf with no children, so its own object ends at the first "]}" after its name.
+    let start = json.find("{\"name\":\"same\"").unwrap();
+    let end = json[start..].find("]}").unwrap() + start + "]}".len();
+    let same = &json[start..end];
+
+    assert_eq!(json_number(same, "count"), 3);
+    let duration_ns = json_number(same, "duration_ns");
+    let min_ns = json_number(same, "min_ns");
+    let max_ns = json_number(same, "max_ns");
+    let avg_ns = json_number(same, "avg_ns");
+    assert!(min_ns <= avg_ns && avg_ns <= max_ns, "min={min_ns} avg={avg_ns} max={max_ns}");
+    assert_eq!(avg_ns, duration_ns / 3);
+}
+
+/// Extracts the first `"key":<digits>` value after `key`'s first occurrence.
+fn json_number(json: &str, key: &str) -> u128 {
+    let needle = format!("\"{key}\":");
+    let start = json.find(&needle).unwrap_or_else(|| panic!("missing {key} in {json}")) + needle.len();
+    let rest = &json[start..];
+    let end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+    rest[..end].parse().unwrap()
+}
+
+fn identical_siblings() {
+    let _root = tracing::info_span!("root").entered();
+    for i in 0..3u64 {
+        let _s = tracing::info_span!("same").entered();
+        thread::sleep(Duration::from_millis(i + 1));
+    }
+}
+
 fn top_level() {
     let _s = tracing::info_span!("top_level").entered();
     for i in 0..4 {
@@ -20,8 +169,9 @@ fn top_level() {
 }
 
 fn middle(i: u64) {
-    let _s = tracing::info_span!("middle").entered();
+    let _s = tracing::info_span!("middle", i).entered();
     thread::sleep(Duration::from_millis(i));
+    tracing::info!(i, "processed middle");
     if i % 2 == 0 {
         leaf()
     }